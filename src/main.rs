@@ -1,11 +1,12 @@
 #![deny(clippy::all)]
 #![deny(clippy::pedantic)]
 
-use cargo::core::Workspace;
-use cargo::ops::Packages;
+use cargo_metadata::{Metadata, MetadataCommand, Package};
+use serde_json::json;
 use std::env::current_dir;
 use std::io::Read;
 use std::path::PathBuf;
+use std::str::FromStr;
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -32,11 +33,55 @@ struct Opt {
 
     #[structopt(long, parse(from_os_str))]
     prefix_path: PathBuf,
+
+    // Like `cargo fmt`, rewrite violating files in place instead of just reporting them.
+    #[structopt(long, alias = "fix")]
+    write: bool,
+
+    #[structopt(long, default_value = "human")]
+    message_format: MessageFormat,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum MessageFormat {
+    Human,
+    Json,
+}
+
+impl FromStr for MessageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(MessageFormat::Human),
+            "json" => Ok(MessageFormat::Json),
+            other => Err(format!("Unknown message format `{other}`")),
+        }
+    }
+}
+
+// Why a violation's prefix check failed.
+enum Reason {
+    TooShort,
+    Mismatch,
+    ReadError,
+}
+
+impl Reason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Reason::TooShort => "too-short",
+            Reason::Mismatch => "mismatch",
+            Reason::ReadError => "read-error",
+        }
+    }
 }
 
 fn main() {
     //        .version(crate_version!())
     let opt = Opt::from_args();
+    let write = opt.write;
+    let message_format = opt.message_format;
 
     let Params {
         paths_to_check,
@@ -56,32 +101,97 @@ fn main() {
 
     for path in &paths_to_check {
         let mut file = std::fs::File::open(path).expect("Error reading source file");
-        let has_prefix = match file.read_exact(&mut buf) {
-            Ok(()) => prefix
-                .bytes()
-                .zip(buf.iter())
-                .all(|(want, got)| want == *got || want == 0x1A),
-            Err(ref err) if err.kind() == std::io::ErrorKind::UnexpectedEof => false,
+        let reason = match file.read_exact(&mut buf) {
+            Ok(()) => {
+                let matches_prefix = prefix
+                    .bytes()
+                    .zip(buf.iter())
+                    .all(|(want, got)| want == *got || want == 0x1A);
+                if matches_prefix {
+                    None
+                } else {
+                    Some(Reason::Mismatch)
+                }
+            }
+            Err(ref err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                Some(Reason::TooShort)
+            }
             Err(ref err) => {
                 eprintln!("Error reading {}: {}", path.display(), err);
-                false
+                Some(Reason::ReadError)
             }
         };
-        if !has_prefix {
+
+        if message_format == MessageFormat::Json {
+            println!(
+                "{}",
+                json!({
+                    "path": path.display().to_string(),
+                    "has_prefix": reason.is_none(),
+                    "reason": reason.as_ref().map(Reason::as_str),
+                })
+            );
+        }
+
+        if reason.is_some() {
             violations.push(path.to_owned());
         }
     }
 
     violations.sort();
 
-    for violation in &violations {
-        println!("{}", violation.display());
-    }
-    if !violations.is_empty() {
-        std::process::exit(1);
+    if write {
+        let mut failed = false;
+        for violation in &violations {
+            match fix_violation(violation, &prefix) {
+                Ok(()) => println!("Fixed {}", violation.display()),
+                Err(err) => {
+                    eprintln!("Error fixing {}: {}", violation.display(), err);
+                    failed = true;
+                }
+            }
+        }
+        if failed {
+            std::process::exit(1);
+        }
+    } else {
+        if message_format == MessageFormat::Human {
+            for violation in &violations {
+                println!("{}", violation.display());
+            }
+        }
+        if !violations.is_empty() {
+            std::process::exit(1);
+        }
     }
 }
 
+// Overwrites the first `prefix.len()` bytes of `path` with `prefix` (honoring `0x1A` wildcards
+// by leaving the original byte in place), or prepends `prefix` if the file is too short for that.
+fn fix_violation(path: &std::path::Path, prefix: &str) -> Result<(), std::io::Error> {
+    let original = std::fs::read(path)?;
+    let prefix = prefix.as_bytes();
+
+    let fixed = if original.len() >= prefix.len() {
+        let mut fixed = Vec::with_capacity(original.len());
+        fixed.extend(
+            prefix
+                .iter()
+                .zip(&original)
+                .map(|(&want, &got)| if want == 0x1A { got } else { want }),
+        );
+        fixed.extend_from_slice(&original[prefix.len()..]);
+        fixed
+    } else {
+        let mut fixed = Vec::with_capacity(prefix.len() + original.len());
+        fixed.extend_from_slice(prefix);
+        fixed.extend_from_slice(&original);
+        fixed
+    };
+
+    std::fs::write(path, fixed)
+}
+
 fn die(message: &str) {
     eprintln!("{}", message);
     std::process::exit(2);
@@ -125,27 +235,309 @@ fn list_paths(manifest_path: PathBuf, packages: &Packages) -> Result<Vec<PathBuf
         manifest_path = current_dir().unwrap().join(manifest_path);
     }
 
-    let config = cargo::util::config::Config::default()
-        .map_err(|err| format!("Error making cargo config: {}", err))?;
-    let workspace = Workspace::new(&manifest_path, &config).unwrap_or_else(|_| {
-        die(&format!("Error parsing {}", manifest_path.display()));
-        unreachable!();
-    });
-
-    Ok(packages
-        .get_packages(&workspace)
+    let metadata = MetadataCommand::new()
+        .manifest_path(&manifest_path)
+        .no_deps()
+        .exec()
+        .unwrap_or_else(|_| {
+            die(&format!("Error parsing {}", manifest_path.display()));
+            unreachable!();
+        });
+
+    let roots = packages
+        .get_packages(&metadata)
         .map_err(|err| format!("{}", err))?
         .into_iter()
-        .flat_map(|package| package.targets())
-        .map(|target| target.src_path().path().to_owned())
-        .collect())
+        .flat_map(|package| package.targets.iter())
+        .map(|target| target.src_path.clone().into_std_path_buf());
+
+    let mut visited = std::collections::HashSet::new();
+    let mut paths = vec![];
+    for root in roots {
+        collect_module_files(&root, &mut visited, &mut paths)?;
+    }
+    Ok(paths)
+}
+
+// Recurses into a target's `mod` declarations so every file in its module tree gets checked.
+fn collect_module_files(
+    path: &std::path::Path,
+    visited: &mut std::collections::HashSet<PathBuf>,
+    out: &mut Vec<PathBuf>,
+) -> Result<(), String> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|err| format!("Error reading {}: {}", path.display(), err))?;
+    if !visited.insert(canonical) {
+        return Ok(());
+    }
+    out.push(path.to_owned());
+
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| format!("Error reading {}: {}", path.display(), err))?;
+    let dir = path.parent().unwrap_or_else(|| std::path::Path::new(""));
+
+    for module in find_mod_declarations(&contents) {
+        collect_module_files(&resolve_module_path(dir, &module), visited, out)?;
+    }
+    Ok(())
+}
+
+struct ModDecl {
+    name: String,
+    path: Option<String>,
+}
+
+fn find_mod_declarations(contents: &str) -> Vec<ModDecl> {
+    let lines: Vec<&str> = contents.lines().collect();
+    lines
+        .iter()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let name = mod_name_from_line(line)?;
+            let path = parse_path_attr(line.trim()).or_else(|| path_attr_above(&lines, i));
+            Some(ModDecl { name, path })
+        })
+        .collect()
+}
+
+// Walks back through the contiguous run of attribute lines directly above `mod_line`, looking
+// for a `#[path = "..."]` among them (it need not be the line immediately above).
+fn path_attr_above(lines: &[&str], mod_line: usize) -> Option<String> {
+    let mut i = mod_line;
+    while i > 0 {
+        i -= 1;
+        let line = lines[i].trim();
+        if let Some(path) = parse_path_attr(line) {
+            return Some(path);
+        }
+        if !line.starts_with("#[") {
+            return None;
+        }
+    }
+    None
+}
+
+// Matches `mod foo;`, `pub mod foo;`, `pub(crate) mod foo;`, `pub(in some::path) mod foo;`,
+// optionally with a same-line attribute (`#[cfg(test)] mod foo;`) or trailing comment
+// (`mod foo; // why`). Deliberately does not match `mod foo { ... }`.
+fn mod_name_from_line(line: &str) -> Option<String> {
+    let code = line.rfind(']').map_or(line, |idx| &line[idx + 1..]).trim();
+    let code = strip_visibility(code);
+
+    let rest = code.strip_prefix("mod ")?.trim_start();
+    let name_end = rest.find(|c: char| !(c.is_alphanumeric() || c == '_'))?;
+    let name = &rest[..name_end];
+    if name.is_empty() {
+        return None;
+    }
+
+    let after = strip_trailing_comment(rest[name_end..].trim());
+    if after == ";" {
+        Some(name.to_owned())
+    } else {
+        None
+    }
+}
+
+// Strips a `pub`, `pub(crate)`, `pub(super)`, or `pub(in some::path)` prefix.
+fn strip_visibility(code: &str) -> &str {
+    if let Some(rest) = code.strip_prefix("pub(") {
+        if let Some(idx) = rest.find(')') {
+            return rest[idx + 1..].trim_start();
+        }
+    }
+    code.strip_prefix("pub").unwrap_or(code).trim_start()
+}
+
+fn strip_trailing_comment(code: &str) -> &str {
+    if let Some(idx) = code.find("//") {
+        return code[..idx].trim_end();
+    }
+    if let Some(idx) = code.find("/*") {
+        if code.trim_end().ends_with("*/") {
+            return code[..idx].trim_end();
+        }
+    }
+    code
+}
+
+fn parse_path_attr(line: &str) -> Option<String> {
+    let rest = line.strip_prefix("#[path")?.trim_start();
+    let rest = rest.strip_prefix('=')?.trim_start();
+    let rest = rest.strip_prefix('"')?;
+    let end = rest.find('"')?;
+    Some(rest[..end].to_owned())
+}
+
+fn resolve_module_path(dir: &std::path::Path, module: &ModDecl) -> PathBuf {
+    if let Some(path) = &module.path {
+        return dir.join(path);
+    }
+    let direct = dir.join(format!("{}.rs", module.name));
+    if direct.exists() {
+        direct
+    } else {
+        dir.join(&module.name).join("mod.rs")
+    }
+}
+
+// Mirrors `cargo::ops::Packages`, resolved against `cargo metadata` output.
+enum Packages {
+    Default,
+    All,
+    OptOut(Vec<String>),
+    Named(Vec<String>),
+}
+
+impl Packages {
+    fn from_flags(all: bool, exclude: Vec<String>, package: Vec<String>) -> Result<Self, String> {
+        Ok(match (all, exclude.len(), package.len()) {
+            (false, 0, 0) => Packages::Default,
+            (false, 0, _) => Packages::Named(package),
+            (false, _, _) => {
+                return Err("--exclude can only be used together with --all".to_owned())
+            }
+            (true, _, 0) if exclude.is_empty() => Packages::All,
+            (true, _, 0) => Packages::OptOut(exclude),
+            (true, _, _) => return Err("Cannot specify --all and --package".to_owned()),
+        })
+    }
+
+    fn get_packages<'a>(&self, metadata: &'a Metadata) -> Result<Vec<&'a Package>, String> {
+        let members = workspace_members(metadata);
+        match self {
+            Packages::Default => Ok(metadata
+                .workspace_default_members
+                .iter()
+                .filter_map(|id| metadata.packages.iter().find(|package| &package.id == id))
+                .collect()),
+            Packages::All => Ok(members),
+            Packages::OptOut(exclude) => {
+                let patterns = compile_patterns(exclude)?;
+                for (pattern, raw) in patterns.iter().zip(exclude) {
+                    if !members.iter().any(|package| pattern.matches(&package.name)) {
+                        return Err(format!("Pattern `{raw}` did not match any packages"));
+                    }
+                }
+                Ok(members
+                    .into_iter()
+                    .filter(|package| !patterns.iter().any(|p| p.matches(&package.name)))
+                    .collect())
+            }
+            Packages::Named(names) => {
+                let patterns = compile_patterns(names)?;
+                let mut selected: Vec<&Package> = vec![];
+                for (pattern, raw) in patterns.iter().zip(names) {
+                    let found: Vec<&Package> = members
+                        .iter()
+                        .filter(|package| pattern.matches(&package.name))
+                        .copied()
+                        .collect();
+                    if found.is_empty() {
+                        return Err(format!("package `{raw}` is not a member of the workspace"));
+                    }
+                    for package in found {
+                        if !selected.iter().any(|m| m.id == package.id) {
+                            selected.push(package);
+                        }
+                    }
+                }
+                Ok(selected)
+            }
+        }
+    }
+}
+
+// Each value is a glob pattern (e.g. `api-*`); plain names still match exactly.
+fn compile_patterns(values: &[String]) -> Result<Vec<glob::Pattern>, String> {
+    values
+        .iter()
+        .map(|value| {
+            glob::Pattern::new(value)
+                .map_err(|err| format!("Invalid package pattern `{value}`: {err}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test_find_mod_declarations {
+    use super::find_mod_declarations;
+
+    fn names(contents: &str) -> Vec<String> {
+        find_mod_declarations(contents)
+            .into_iter()
+            .map(|module| module.name)
+            .collect()
+    }
+
+    #[test]
+    fn finds_plain_and_visible_mods() {
+        assert_eq!(
+            names("mod foo;\npub mod bar;\npub(crate) mod baz;\n"),
+            vec!["foo", "bar", "baz"]
+        );
+    }
+
+    #[test]
+    fn skips_inline_mods() {
+        assert_eq!(names("mod foo {\n    fn f() {}\n}\n"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn includes_cfg_gated_mods() {
+        assert_eq!(names("#[cfg(test)]\nmod tests;\n"), vec!["tests"]);
+        assert_eq!(names("#[cfg(test)] mod tests;\n"), vec!["tests"]);
+    }
+
+    #[test]
+    fn ignores_trailing_comments() {
+        assert_eq!(names("mod foo; // trailing comment\n"), vec!["foo"]);
+        assert_eq!(names("mod foo; /* trailing comment */\n"), vec!["foo"]);
+    }
+
+    #[test]
+    fn finds_pub_in_path_mods() {
+        assert_eq!(names("pub(in crate::foo) mod bar;\n"), vec!["bar"]);
+    }
+
+    #[test]
+    fn honors_path_attribute() {
+        let modules = find_mod_declarations("#[path = \"imp.rs\"]\nmod foo;\n");
+        assert_eq!(modules.len(), 1);
+        assert_eq!(modules[0].name, "foo");
+        assert_eq!(modules[0].path.as_deref(), Some("imp.rs"));
+    }
+
+    #[test]
+    fn honors_path_attribute_stacked_above_another_attribute() {
+        let modules = find_mod_declarations("#[path = \"unix.rs\"]\n#[cfg(unix)]\nmod imp;\n");
+        assert_eq!(modules.len(), 1);
+        assert_eq!(modules[0].name, "imp");
+        assert_eq!(modules[0].path.as_deref(), Some("unix.rs"));
+    }
+
+    #[test]
+    fn honors_same_line_path_attribute() {
+        let modules = find_mod_declarations("#[path = \"foo.rs\"] mod bar;\n");
+        assert_eq!(modules.len(), 1);
+        assert_eq!(modules[0].name, "bar");
+        assert_eq!(modules[0].path.as_deref(), Some("foo.rs"));
+    }
+}
+
+fn workspace_members(metadata: &Metadata) -> Vec<&Package> {
+    metadata
+        .packages
+        .iter()
+        .filter(|package| metadata.workspace_members.contains(&package.id))
+        .collect()
 }
 
 #[cfg(test)]
 mod test_list_paths {
-    use cargo::core::Workspace;
-    use cargo::ops::Packages;
-    use cargo::Config;
+    use super::Packages;
+    use cargo_metadata::MetadataCommand;
     use std::collections::HashSet;
     use std::env::current_dir;
     use std::path::PathBuf;
@@ -180,12 +572,12 @@ mod test_list_paths {
     #[test]
     fn workspace_package_list() {
         assert_packages(
-            &Packages::Packages(vec!["wbin".to_owned()].into_iter().collect()),
+            &Packages::Named(vec!["wbin".to_owned()].into_iter().collect()),
             "tests/projects/workspace_root/Cargo.toml",
             &["wbin"],
         );
         assert_packages(
-            &Packages::Packages(
+            &Packages::Named(
                 vec!["wbin", "workspace_root"]
                     .into_iter()
                     .map(str::to_owned)
@@ -200,13 +592,33 @@ mod test_list_paths {
     fn workspace_package_not_found() {
         assert_eq!(
             packages(
-                &Packages::Packages(vec!["doesnotexist".to_owned()].into_iter().collect()),
+                &Packages::Named(vec!["doesnotexist".to_owned()].into_iter().collect()),
                 "tests/projects/workspace_root/Cargo.toml"
             ),
             Err("package `doesnotexist` is not a member of the workspace".to_owned())
         );
     }
 
+    #[test]
+    fn workspace_package_glob_list() {
+        assert_packages(
+            &Packages::Named(vec!["w*".to_owned()].into_iter().collect()),
+            "tests/projects/workspace_root/Cargo.toml",
+            &["workspace_root", "wbin", "wlib"],
+        );
+    }
+
+    #[test]
+    fn workspace_package_glob_not_found() {
+        assert_eq!(
+            packages(
+                &Packages::Named(vec!["doesnotexist*".to_owned()].into_iter().collect()),
+                "tests/projects/workspace_root/Cargo.toml"
+            ),
+            Err("package `doesnotexist*` is not a member of the workspace".to_owned())
+        );
+    }
+
     #[test]
     fn manifest_is_in_workspace() {
         assert_packages(
@@ -220,20 +632,23 @@ mod test_list_paths {
             &["workspace_root", "wbin", "wlib"],
         );
         assert_packages(
-            &Packages::Packages(vec!["wbin".to_owned()].into_iter().collect()),
+            &Packages::Named(vec!["wbin".to_owned()].into_iter().collect()),
             "tests/projects/workspace_root/Cargo.toml",
             &["wbin"],
         );
     }
 
     fn packages(spec: &Packages, manifest_path: &str) -> Result<HashSet<String>, String> {
-        let config = Config::default().unwrap();
-        let workspace = Workspace::new(&abs(manifest_path), &config).unwrap();
+        let metadata = MetadataCommand::new()
+            .manifest_path(abs(manifest_path))
+            .no_deps()
+            .exec()
+            .unwrap();
         Ok(spec
-            .get_packages(&workspace)
+            .get_packages(&metadata)
             .map_err(|e| format!("{}", e))?
             .into_iter()
-            .map(|p| p.name().as_str().to_owned())
+            .map(|p| p.name.clone())
             .collect())
     }
 
@@ -113,6 +113,33 @@ fn file_too_short() {
     assert_eq!(StringOutput::new(false, &want_stdout, ""), output.into());
 }
 
+#[test]
+fn json_message_format() {
+    let main_rs = current_dir()
+        .unwrap()
+        .join("tests")
+        .join("projects")
+        .join("workspace_root")
+        .join("wbin")
+        .join("src")
+        .join("main.rs");
+
+    let output = run_command(&[
+        "--prefix-path=tests/prefixes/long.txt",
+        "--manifest-path=tests/projects/workspace_root/Cargo.toml",
+        "-p",
+        "wbin",
+        "--message-format=json",
+    ]);
+
+    let want_stdout = format!(
+        "{{\"has_prefix\":false,\"path\":\"{}\",\"reason\":\"mismatch\"}}\n",
+        main_rs.display()
+    );
+
+    assert_eq!(StringOutput::new(false, &want_stdout, ""), output.into());
+}
+
 #[test]
 fn package_not_found() {
     let output = run_command(&[
@@ -177,6 +204,82 @@ fn prefix_file_not_found() {
     );
 }
 
+// Restores a fixture file to its original contents on drop, even if an assertion panics first.
+struct RestoreFixtureOnDrop {
+    path: PathBuf,
+    original: Vec<u8>,
+}
+
+impl Drop for RestoreFixtureOnDrop {
+    fn drop(&mut self) {
+        std::fs::write(&self.path, &self.original).unwrap();
+    }
+}
+
+#[test]
+fn write_fixes_violations() {
+    let workspace_root = current_dir()
+        .unwrap()
+        .join("tests")
+        .join("projects")
+        .join("workspace_root");
+    let main_rs = workspace_root.join("wbin").join("src").join("main.rs");
+
+    let original = std::fs::read(&main_rs).unwrap();
+    let _restore = RestoreFixtureOnDrop {
+        path: main_rs.clone(),
+        original: original.clone(),
+    };
+
+    let output = run_command(&[
+        "--prefix-path=tests/prefixes/long.txt",
+        "--manifest-path=tests/projects/workspace_root/Cargo.toml",
+        "-p",
+        "wbin",
+        "--write",
+    ]);
+
+    let want_stdout = format!("Fixed {}\n", main_rs.display());
+    assert_eq!(StringOutput::new(true, &want_stdout, ""), output.into());
+
+    let prefix = std::fs::read("tests/prefixes/long.txt").unwrap();
+    let mut want_contents = prefix.clone();
+    want_contents.extend_from_slice(&original[prefix.len()..]);
+    assert_eq!(want_contents, std::fs::read(&main_rs).unwrap());
+}
+
+#[test]
+fn write_fixes_too_short_violation() {
+    let workspace_root = current_dir()
+        .unwrap()
+        .join("tests")
+        .join("projects")
+        .join("workspace_root");
+    let main_rs = workspace_root.join("wbin").join("src").join("main.rs");
+
+    let original = std::fs::read(&main_rs).unwrap();
+    let _restore = RestoreFixtureOnDrop {
+        path: main_rs.clone(),
+        original: original.clone(),
+    };
+
+    let output = run_command(&[
+        "--prefix-path=tests/prefixes/really_long.txt",
+        "--manifest-path=tests/projects/workspace_root/Cargo.toml",
+        "-p",
+        "wbin",
+        "--write",
+    ]);
+
+    let want_stdout = format!("Fixed {}\n", main_rs.display());
+    assert_eq!(StringOutput::new(true, &want_stdout, ""), output.into());
+
+    let prefix = std::fs::read("tests/prefixes/really_long.txt").unwrap();
+    let mut want_contents = prefix;
+    want_contents.extend_from_slice(&original);
+    assert_eq!(want_contents, std::fs::read(&main_rs).unwrap());
+}
+
 #[test]
 fn all_and_package() {
     let output = run_command(&[